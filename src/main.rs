@@ -1,143 +1,184 @@
 use async_std::io;
-use libp2p::{identity, mdns::{Mdns, MdnsConfig, MdnsEvent}, swarm::{Swarm, SwarmEvent}, PeerId};
-use std::error::Error;
-use futures::executor::block_on;
-use futures::{prelude::*, select};
+use async_std::task;
+use futures::prelude::*;
 use libp2p::kad::record::store::MemoryStore;
-use libp2p::kad::{
-    record::Key, Kademlia, KademliaEvent, PutRecordOk, QueryResult, Quorum, Record,
-};
-use libp2p::{
-    development_transport,
-    swarm::{NetworkBehaviourEventProcess},
-    NetworkBehaviour,
-};
 use libp2p::kad::store::RecordStore;
+use libp2p::kad::{record::Key, Kademlia, KademliaConfig, Record};
+use libp2p::mdns::{Mdns, MdnsConfig};
+use libp2p::multiaddr::Protocol;
+use libp2p::request_response::{ProtocolSupport, RequestResponse};
+use libp2p::{development_transport, identity, Multiaddr, PeerId};
+use std::collections::HashMap;
+use std::iter;
+
+use namespace::{NamespaceConfig, Router};
+use network::{Client, MyBehaviour};
+use store::{DiskStore, KvStore};
+use transfer::{FileExchangeCodec, FileExchangeProtocol};
 
 /// We'll put our errors in an `errors` module, and other modules in this crate will
 /// `use crate::errors::*;` to get access to everything `error_chain` creates.
 pub mod errors;
+/// Multi-tenant key spaces: several isolated, namespaced Kademlia DHTs in one process.
+pub mod namespace;
+/// The swarm-driving event loop and the `Client` handle used to talk to it.
+pub mod network;
+/// `RecordStore` implementations: in-memory, and persistent on-disk.
+pub mod store;
+/// The `PROVIDE`/`FETCH` request/response protocol for values too large for a record.
+pub mod transfer;
 
 #[async_std::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Create a random key for ourselves.
     let local_key = identity::Keypair::generate_ed25519();
     let local_peer_id = PeerId::from(local_key.public());
     println!("My Peer Id: {}", local_peer_id);
 
-    // Set up a an encrypted DNS-enabled TCP Transport over the Mplex protocol.
-    let transport = development_transport(local_key).await?;
-
-    let client = std::env::args().skip(1).next() == Some("client".into());
-    if client {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let client_mode = args.first().map(String::as_str) == Some("client");
+    if client_mode {
         println!("Started in CLIENT mode");
     } else {
         println!("Started in SERVER mode");
     }
+    // `--store-path <dir>` makes the node's records survive a restart instead of only
+    // living in memory for the lifetime of the process.
+    let store_path = args
+        .iter()
+        .position(|arg| arg == "--store-path")
+        .and_then(|index| args.get(index + 1))
+        .map(std::path::PathBuf::from);
+    // `--bootstrap <multiaddr>` (repeatable) seeds the routing table with peers outside
+    // this node's LAN, where mDNS can't find them.
+    let bootstrap_peers = parse_bootstrap_peers(&args)?;
+    // `--namespace <name>[:<protocol>]` (repeatable) runs one isolated Kademlia DHT per
+    // namespace, keyed off a distinct protocol name so it won't cross-talk with other
+    // namespaces or unrelated DHTs (e.g. IPFS) sharing the LAN.
+    let namespaces = namespace::parse_namespaces(&args);
 
-    // We create a custom network behaviour that combines Kademlia and mDNS.
-    #[derive(NetworkBehaviour)]
-    #[behaviour(event_process = true)]
-    struct MyBehaviour {
-        kademlia: Kademlia<MemoryStore>,
-        mdns: Mdns,
+    // Every namespace gets its own transport, listener, Mdns and Kademlia instance, but
+    // shares this node's identity, so discovering one namespace's peers is enough to
+    // recognise the same physical node across all of them. Note this means mDNS discovery
+    // is per-namespace, not shared: a peer found in one namespace isn't automatically
+    // added to any other namespace's Kademlia instance (see `namespace` module docs).
+    let mut clients = HashMap::new();
+    for config in namespaces {
+        let name = config.name.clone();
+        let client = run_namespace(
+            local_key.clone(),
+            local_peer_id,
+            client_mode,
+            store_path.clone(),
+            &bootstrap_peers,
+            config,
+        )
+        .await?;
+        clients.insert(name, client);
     }
+    let router = Router::new(clients);
 
-    impl NetworkBehaviourEventProcess<MdnsEvent> for MyBehaviour {
-        // Called when `mdns` produces an event.
-        fn inject_event(&mut self, event: MdnsEvent) {
-            if let MdnsEvent::Discovered(list) = event {
-                for (peer_id, multiaddr) in list {
-                    println!("New Peer '{}' at {} added to network", peer_id, multiaddr);
-                    self.kademlia.add_address(&peer_id, multiaddr);
-                }
-            }
+    // loop - processing commands from stdin, or just idling in server mode
+    if client_mode {
+        let mut stdin = io::BufReader::new(io::stdin()).lines().fuse();
+        while let Some(line) = stdin.next().await {
+            handle_input_line(&router, line.expect("Stdin not to close"));
         }
+    } else {
+        future::pending::<()>().await;
     }
 
-    impl NetworkBehaviourEventProcess<KademliaEvent> for MyBehaviour {
-        fn inject_event(&mut self, message: KademliaEvent) {
-            match message {
-                KademliaEvent::OutboundQueryCompleted { result, .. } => {
-                    match result {
-                        QueryResult::GetRecord(Ok(ok)) => {
-                            for peer_record in ok.records
-                            {
-                                println!(
-                                    "Got record {:?} {:?} from peer {:?}",
-                                    std::str::from_utf8(peer_record.record.key.as_ref()).unwrap(),
-                                    std::str::from_utf8(&peer_record.record.value).unwrap(),
-                                    peer_record.peer
-                                );
-                            }
-                        }
-                        QueryResult::GetRecord(Err(err)) => {
-                            eprintln!("Failed to get record: {:?}", err);
-                        }
-                        QueryResult::PutRecord(Ok(PutRecordOk { key })) => {
-                            println!(
-                                "Successfully put record {:?}",
-                                std::str::from_utf8(key.as_ref()).unwrap()
-                            );
-                        }
-                        QueryResult::PutRecord(Err(err)) => {
-                            eprintln!("Failed to put record: {:?}", err);
-                        }
-                        _ => {}
-                    }
-                },
-                _ => {}
-            }
-        }
-    }
+    Ok(())
+}
+
+/// Build and spawn the swarm for a single namespace, returning a `Client` handle to it.
+async fn run_namespace(
+    local_key: identity::Keypair,
+    local_peer_id: PeerId,
+    client_mode: bool,
+    store_path: Option<std::path::PathBuf>,
+    bootstrap_peers: &[(PeerId, Multiaddr)],
+    namespace: NamespaceConfig,
+) -> errors::Result<Client> {
+    // Set up a an encrypted DNS-enabled TCP Transport over the Mplex protocol.
+    let transport = development_transport(local_key).await?;
+
+    let store_path = store_path.map(|path| path.join(&namespace.name));
+    let mut kademlia_config = KademliaConfig::default();
+    kademlia_config.set_protocol_names(vec![namespace.protocol.clone().into_bytes().into()]);
 
     // Create a swarm to manage peers and events.
+    let (response_sender, response_receiver) = network::response_channel();
     let mut swarm = {
-        // Create a Kademlia behaviour.
-        let store = create_store(local_peer_id, client)?;
-        let kademlia = Kademlia::new(local_peer_id, store);
-        let mdns = block_on(Mdns::new(MdnsConfig::default()))?;
-        let behaviour = MyBehaviour { kademlia, mdns };
-        Swarm::new(transport, behaviour, local_peer_id)
+        let store = create_store(local_peer_id, client_mode, store_path)?;
+        let kademlia = Kademlia::with_config(local_peer_id, store, kademlia_config);
+        let mdns = Mdns::new(MdnsConfig::default()).await?;
+        let request_response = RequestResponse::new(
+            FileExchangeCodec::default(),
+            iter::once((FileExchangeProtocol, ProtocolSupport::Full)),
+            Default::default(),
+        );
+        let behaviour =
+            MyBehaviour::new(kademlia, mdns, request_response, response_sender, local_peer_id);
+        libp2p::Swarm::new(transport, behaviour, local_peer_id)
     };
 
-    // Read full lines from stdin
-    let mut stdin = io::BufReader::new(io::stdin()).lines().fuse();
-
     // Listen on all interfaces and whatever port the OS assigns.
     swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
 
-    // loop - processing commands from stdin or events from the network
-    if client {
-        loop {
-            select! {
-                line = stdin.select_next_some() => handle_input_line(&mut swarm.behaviour_mut().kademlia,
-                    line.expect("Stdin not to close"))?,
-                event = swarm.select_next_some() => match event {
-                    SwarmEvent::NewListenAddr { address, .. } => {
-                        println!("Listening on {:?}", address);
-                    },
-                    _ => {}
-                }
-            }
-        }
-    } else {
-        loop {
-            select! {
-            event = swarm.select_next_some() => match event {
-                SwarmEvent::NewListenAddr { address, .. } => {
-                    println!("Listening on {:?}", address);
-                },
-                _ => {}
-            }
+    // Seed the routing table with the configured bootstrap peers and start a bootstrap
+    // query before handing the swarm off to the event loop (which drives that query to
+    // completion), so GETs/PUTs aren't issued against an empty table.
+    if !bootstrap_peers.is_empty() {
+        for (peer_id, addr) in bootstrap_peers {
+            swarm.behaviour_mut().kademlia.add_address(peer_id, addr.clone());
+            swarm.dial(addr.clone())?;
         }
-        }
-
+        swarm.behaviour_mut().kademlia.bootstrap()?;
     }
+
+    // Hand the swarm off to the event loop and keep only a `Client` handle to it.
+    let (client, event_loop) = network::new(swarm, response_receiver);
+    task::spawn(event_loop);
+
+    Ok(client)
+}
+
+/// Collect the `(PeerId, Multiaddr)` pair for every `--bootstrap <multiaddr>` argument.
+///
+/// Each multiaddr must end in a `/p2p/<peer id>` component, since `kademlia.add_address`
+/// needs the peer id to seed the routing table before dialing.
+fn parse_bootstrap_peers(args: &[String]) -> errors::Result<Vec<(PeerId, Multiaddr)>> {
+    args.iter()
+        .enumerate()
+        .filter(|(_, arg)| *arg == "--bootstrap")
+        .map(|(index, _)| {
+            let addr: Multiaddr = args
+                .get(index + 1)
+                .ok_or("--bootstrap requires a multiaddr")?
+                .parse()
+                .map_err(|_| "Invalid bootstrap multiaddr")?;
+            let peer_id = match addr.iter().last() {
+                Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).ok(),
+                _ => None,
+            }
+            .ok_or("Bootstrap multiaddr must end in /p2p/<peer id>")?;
+            Ok((peer_id, addr))
+        })
+        .collect()
 }
 
-fn create_store(peer_id: PeerId, client: bool) -> errors::Result<MemoryStore> {
-    let mut store = MemoryStore::new(peer_id);
+/// Build the `RecordStore` for this node: persistent if `store_path` was given, otherwise
+/// an in-memory store that is lost when the process exits.
+fn create_store(
+    peer_id: PeerId,
+    client: bool,
+    store_path: Option<std::path::PathBuf>,
+) -> errors::Result<KvStore> {
+    let mut store = match store_path {
+        Some(path) => KvStore::Disk(DiskStore::open(path)?),
+        None => KvStore::Memory(MemoryStore::new(peer_id)),
+    };
 
     if !client {
         store.put(Record::new(Key::new(&"andrew"), Vec::from("55")))?;
@@ -146,42 +187,119 @@ fn create_store(peer_id: PeerId, client: bool) -> errors::Result<MemoryStore> {
     Ok(store)
 }
 
-/*
-fn preload_store(kademlia: &mut Kademlia<MemoryStore>) -> crate::errors::Result<()> {
-    let record = Record::new(Key::new(&"andrew"), Vec::from("55"));
-    kademlia.put_record(record, Quorum::One )?;
-
-    Ok(())
-}
-*/
-
-fn put_record(kademlia: &mut Kademlia<MemoryStore>, key: Key, value: Vec<u8>) -> errors::Result<()> {
-    let record = Record {
-        key,
-        value,
-        publisher: None,
-        expires: None,
-    };
-    kademlia.put_record(record, Quorum::One)?;
-
-    Ok(())
-}
-
-fn handle_input_line(kademlia: &mut Kademlia<MemoryStore>, line: String) -> errors::Result<()> {
+/// Parse a `GET`/`PUT`/`PROVIDE`/`FETCH` command from a stdin `line` and issue it against
+/// `router`, spawning a task to await the reply so the stdin loop isn't blocked on the
+/// network.
+///
+/// A key of the form `<namespace>:<key>` is routed to that namespace's DHT; a bare key
+/// goes to [`namespace::DEFAULT_NAMESPACE`]. `PROVIDE <key> <path>` advertises this node
+/// as the holder of the file at `path` without putting its bytes in a DHT record, and
+/// `FETCH <key>` resolves a provider for `key` and pulls the bytes directly from them.
+fn handle_input_line(router: &Router, line: String) {
     let mut args = line.split(' ');
+    let router = router.clone();
 
-    match &args.next().map(|s| s.to_ascii_uppercase()).ok_or("Could not parse input string")? as &str {
+    match &args.next().map(|s| s.to_ascii_uppercase()).unwrap_or_default() as &str {
         "GET" => {
-            let key = Key::new(&args.next().ok_or("Expected key")?);
-            kademlia.get_record(key, Quorum::One);
+            let key = match args.next() {
+                Some(key) => key.as_bytes().to_vec(),
+                None => return eprintln!("Expected key"),
+            };
+            task::spawn(async move {
+                match router.get(&key).await {
+                    Ok(Some(value)) => println!(
+                        "Got record {:?}",
+                        std::str::from_utf8(&value).unwrap_or("<invalid utf8>")
+                    ),
+                    Ok(None) => println!("Record not found"),
+                    Err(err) => eprintln!("Failed to get record: {:?}", err),
+                }
+            });
+        }
+        "PUT" => {
+            let key = match args.next() {
+                Some(key) => key.as_bytes().to_vec(),
+                None => return eprintln!("Expected key"),
+            };
+            let value = match args.next() {
+                Some(value) => value.as_bytes().to_vec(),
+                None => return eprintln!("Expected value"),
+            };
+            task::spawn(async move {
+                match router.put(&key, value).await {
+                    Ok(()) => println!("Successfully put record"),
+                    Err(err) => eprintln!("Failed to put record: {:?}", err),
+                }
+            });
+        }
+        "PROVIDE" => {
+            let key = match args.next() {
+                Some(key) => key.as_bytes().to_vec(),
+                None => return eprintln!("Expected key"),
+            };
+            let path = match args.next() {
+                Some(path) => std::path::PathBuf::from(path),
+                None => return eprintln!("Expected path"),
+            };
+            task::spawn(async move {
+                match router.provide(&key, path).await {
+                    Ok(()) => println!("Now providing record"),
+                    Err(err) => eprintln!("Failed to start providing: {:?}", err),
+                }
+            });
+        }
+        "FETCH" => {
+            let key = match args.next() {
+                Some(key) => key.as_bytes().to_vec(),
+                None => return eprintln!("Expected key"),
+            };
+            task::spawn(async move {
+                match router.fetch(&key).await {
+                    Ok(value) => println!(
+                        "Fetched {} bytes: {:?}",
+                        value.len(),
+                        std::str::from_utf8(&value).unwrap_or("<invalid utf8>")
+                    ),
+                    Err(err) => eprintln!("Failed to fetch record: {:?}", err),
+                }
+            });
         }
-        "PUT" => put_record(kademlia,
-                            Key::new(&args.next().ok_or("Expected key")?),
-                            args.next().ok_or("Expected value")?.as_bytes().to_vec() )?,
         _ => {
-            eprintln!("expected GET or PUT");
+            eprintln!("expected GET, PUT, PROVIDE or FETCH");
         }
     }
+}
 
-    Ok(())
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity;
+
+    #[test]
+    fn parse_bootstrap_peers_reads_the_trailing_p2p_component() {
+        let peer_id = PeerId::from(identity::Keypair::generate_ed25519().public());
+        let args: Vec<String> = vec![
+            "--bootstrap".into(),
+            format!("/ip4/127.0.0.1/tcp/4001/p2p/{}", peer_id),
+        ];
+
+        let peers = parse_bootstrap_peers(&args).expect("parses");
+
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].0, peer_id);
+    }
+
+    #[test]
+    fn parse_bootstrap_peers_rejects_a_multiaddr_missing_the_peer_id() {
+        let args: Vec<String> = vec!["--bootstrap".into(), "/ip4/127.0.0.1/tcp/4001".into()];
+
+        assert!(parse_bootstrap_peers(&args).is_err());
+    }
+
+    #[test]
+    fn parse_bootstrap_peers_rejects_a_malformed_multiaddr() {
+        let args: Vec<String> = vec!["--bootstrap".into(), "not-a-multiaddr".into()];
+
+        assert!(parse_bootstrap_peers(&args).is_err());
+    }
+}
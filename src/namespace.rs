@@ -0,0 +1,150 @@
+//! Multi-tenant key spaces: running several isolated Kademlia DHTs from one process.
+//!
+//! Each [`NamespaceConfig`] picks a distinct Kademlia protocol name, so its node only
+//! talks to peers running the same namespace rather than cross-talking with IPFS or other
+//! unrelated DHTs on the LAN. [`Router`] then dispatches a `GET`/`PUT` to the right
+//! namespace's [`Client`] by a `<namespace>:<key>` prefix on the key.
+//!
+//! This is a simpler design than a single shared discovery path feeding several named
+//! Kademlia instances: `run_namespace` gives each namespace its own transport, listener
+//! and `Mdns` instance rather than fanning one `Mdns` out to every instance, so a peer
+//! discovered in one namespace is invisible to the others until it's independently
+//! discovered there too. That costs a socket/listener per namespace instead of one shared
+//! discovery path, but keeps each namespace's swarm fully independent.
+
+use crate::errors;
+use crate::network::Client;
+use std::collections::HashMap;
+
+/// The default namespace used for keys with no `<namespace>:` prefix.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// A namespace to join: a name operators use in key prefixes, and the Kademlia protocol
+/// name that keeps its DHT isolated from other namespaces.
+pub struct NamespaceConfig {
+    pub name: String,
+    pub protocol: String,
+}
+
+impl NamespaceConfig {
+    fn new(name: String, protocol: Option<String>) -> Self {
+        let protocol = protocol.unwrap_or_else(|| format!("/libp2pkvs/{}/kad/1.0.0", name));
+        NamespaceConfig { name, protocol }
+    }
+}
+
+/// Parse every `--namespace <name>[:<protocol>]` argument, defaulting to a single
+/// [`DEFAULT_NAMESPACE`] namespace with the crate's default protocol name when none were
+/// given, so a node with no namespace configuration still behaves as a single DHT.
+pub fn parse_namespaces(args: &[String]) -> Vec<NamespaceConfig> {
+    let configs: Vec<_> = args
+        .iter()
+        .enumerate()
+        .filter(|(_, arg)| *arg == "--namespace")
+        .filter_map(|(index, _)| args.get(index + 1))
+        .map(|spec| match spec.split_once(':') {
+            Some((name, protocol)) => NamespaceConfig::new(name.to_string(), Some(protocol.to_string())),
+            None => NamespaceConfig::new(spec.clone(), None),
+        })
+        .collect();
+
+    if configs.is_empty() {
+        vec![NamespaceConfig::new(DEFAULT_NAMESPACE.to_string(), None)]
+    } else {
+        configs
+    }
+}
+
+/// Split a raw key into its namespace prefix (if any) and the remaining key bytes.
+///
+/// A key of `"images:logo.png"` belongs to the `images` namespace; a bare `"logo.png"`
+/// belongs to [`DEFAULT_NAMESPACE`].
+fn split_namespace(key: &[u8]) -> (&str, &[u8]) {
+    match key.iter().position(|&b| b == b':') {
+        Some(index) => match std::str::from_utf8(&key[..index]) {
+            Ok(namespace) => (namespace, &key[index + 1..]),
+            Err(_) => (DEFAULT_NAMESPACE, key),
+        },
+        None => (DEFAULT_NAMESPACE, key),
+    }
+}
+
+/// Dispatches GET/PUT to the `Client` for whichever namespace a key's prefix names.
+#[derive(Clone)]
+pub struct Router {
+    clients: HashMap<String, Client>,
+}
+
+impl Router {
+    pub fn new(clients: HashMap<String, Client>) -> Self {
+        Router { clients }
+    }
+
+    pub async fn get(&self, key: &[u8]) -> errors::Result<Option<Vec<u8>>> {
+        let (namespace, key) = split_namespace(key);
+        self.client_for(namespace)?.get(key.to_vec()).await
+    }
+
+    pub async fn put(&self, key: &[u8], value: Vec<u8>) -> errors::Result<()> {
+        let (namespace, key) = split_namespace(key);
+        self.client_for(namespace)?.put(key.to_vec(), value).await
+    }
+
+    pub async fn provide(&self, key: &[u8], path: std::path::PathBuf) -> errors::Result<()> {
+        let (namespace, key) = split_namespace(key);
+        self.client_for(namespace)?.provide(key.to_vec(), path).await
+    }
+
+    pub async fn fetch(&self, key: &[u8]) -> errors::Result<Vec<u8>> {
+        let (namespace, key) = split_namespace(key);
+        self.client_for(namespace)?.fetch(key.to_vec()).await
+    }
+
+    fn client_for(&self, namespace: &str) -> errors::Result<&Client> {
+        self.clients
+            .get(namespace)
+            .ok_or_else(|| format!("Unknown namespace {:?}", namespace).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_namespaces_defaults_to_a_single_default_namespace() {
+        let configs = parse_namespaces(&[]);
+
+        assert_eq!(configs.len(), 1);
+        assert_eq!(configs[0].name, DEFAULT_NAMESPACE);
+        assert_eq!(configs[0].protocol, format!("/libp2pkvs/{}/kad/1.0.0", DEFAULT_NAMESPACE));
+    }
+
+    #[test]
+    fn parse_namespaces_uses_the_explicit_protocol_override() {
+        let args: Vec<String> = vec!["--namespace".into(), "images:/myapp/images/1.0.0".into()];
+
+        let configs = parse_namespaces(&args);
+
+        assert_eq!(configs.len(), 1);
+        assert_eq!(configs[0].name, "images");
+        assert_eq!(configs[0].protocol, "/myapp/images/1.0.0");
+    }
+
+    #[test]
+    fn split_namespace_splits_on_the_first_colon() {
+        assert_eq!(split_namespace(b"images:logo.png"), ("images", &b"logo.png"[..]));
+    }
+
+    #[test]
+    fn split_namespace_with_no_colon_falls_back_to_default() {
+        assert_eq!(split_namespace(b"logo.png"), (DEFAULT_NAMESPACE, &b"logo.png"[..]));
+    }
+
+    #[test]
+    fn split_namespace_with_non_utf8_prefix_falls_back_to_default() {
+        let key = [0xff, 0x00, b':', b'v'];
+
+        assert_eq!(split_namespace(&key), (DEFAULT_NAMESPACE, &key[..]));
+    }
+}
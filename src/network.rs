@@ -0,0 +1,458 @@
+//! The swarm-driving event loop and the [`Client`] handle used to talk to it.
+//!
+//! `run` owns the `Swarm<MyBehaviour>` and is the only place that touches it once the
+//! node has started: everything else goes through a cloneable [`Client`], which sends
+//! [`Command`]s down an `mpsc` channel and awaits the matching `oneshot` reply. This lets
+//! the KVS be embedded in a larger application instead of only being driven from stdin.
+
+use crate::errors::{self, ErrorKind};
+use crate::store::KvStore;
+use crate::transfer::{FileExchangeCodec, FileRequest, FileResponse};
+use async_std::task;
+use futures::channel::{mpsc, oneshot};
+use futures::prelude::*;
+use libp2p::kad::{
+    record::Key, GetProvidersOk, GetRecordError, GetRecordOk, Kademlia, KademliaEvent,
+    PutRecordOk, QueryId, QueryResult, Quorum, Record,
+};
+use libp2p::mdns::{Mdns, MdnsEvent};
+use libp2p::request_response::{
+    RequestId, RequestResponse, RequestResponseEvent, RequestResponseMessage, ResponseChannel,
+};
+use libp2p::swarm::{NetworkBehaviourEventProcess, Swarm, SwarmEvent};
+use libp2p::{NetworkBehaviour, PeerId};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// A file-exchange response that finished reading off the event loop, paired with the
+/// `ResponseChannel` it should be delivered on. Fed through [`response_channel`] so `run`
+/// can hand it to `request_response.send_response` without the read itself blocking the
+/// swarm.
+type PendingResponse = (ResponseChannel<FileResponse>, FileResponse);
+
+/// Build the channel used to carry [`PendingResponse`]s from the async file read spawned
+/// in `MyBehaviour`'s request handler back to [`run`], which owns the swarm.
+pub fn response_channel() -> (
+    mpsc::UnboundedSender<PendingResponse>,
+    mpsc::UnboundedReceiver<PendingResponse>,
+) {
+    mpsc::unbounded()
+}
+
+/// A cloneable handle for issuing GET/PUT requests against the DHT.
+///
+/// Each method sends a [`Command`] to the event loop spawned by [`run`] and awaits the
+/// `oneshot` reply that the loop completes once the matching `QueryResult` arrives.
+#[derive(Clone)]
+pub struct Client {
+    sender: mpsc::Sender<Command>,
+}
+
+impl Client {
+    /// Look up `key` in the DHT, returning `None` if no record was found.
+    pub async fn get(&self, key: Vec<u8>) -> errors::Result<Option<Vec<u8>>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(Command::Get { key, sender })
+            .await
+            .map_err(|_| ErrorKind::Msg("Event loop has stopped".into()))?;
+        receiver
+            .await
+            .map_err(|_| ErrorKind::Msg("Event loop dropped the response channel".into()))?
+    }
+
+    /// Store `value` under `key` in the DHT.
+    pub async fn put(&self, key: Vec<u8>, value: Vec<u8>) -> errors::Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(Command::Put { key, value, sender })
+            .await
+            .map_err(|_| ErrorKind::Msg("Event loop has stopped".into()))?;
+        receiver
+            .await
+            .map_err(|_| ErrorKind::Msg("Event loop dropped the response channel".into()))?
+    }
+
+    /// Advertise this node as a provider of the (too-large-for-a-record) value at `path`,
+    /// keyed by `key`. Peers that resolve `key`'s providers can then fetch `path`'s bytes
+    /// directly from this node over the file-exchange protocol.
+    pub async fn provide(&self, key: Vec<u8>, path: PathBuf) -> errors::Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(Command::Provide { key, path, sender })
+            .await
+            .map_err(|_| ErrorKind::Msg("Event loop has stopped".into()))?;
+        receiver
+            .await
+            .map_err(|_| ErrorKind::Msg("Event loop dropped the response channel".into()))?
+    }
+
+    /// Resolve a provider for `key` and fetch its bytes directly from them, bypassing the
+    /// DHT record size cap entirely.
+    pub async fn fetch(&self, key: Vec<u8>) -> errors::Result<Vec<u8>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .clone()
+            .send(Command::Fetch { key, sender })
+            .await
+            .map_err(|_| ErrorKind::Msg("Event loop has stopped".into()))?;
+        receiver
+            .await
+            .map_err(|_| ErrorKind::Msg("Event loop dropped the response channel".into()))?
+    }
+}
+
+/// A request enqueued on the command channel, paired with the `oneshot::Sender` the
+/// event loop uses to deliver the eventual `QueryResult`.
+enum Command {
+    Get {
+        key: Vec<u8>,
+        sender: oneshot::Sender<errors::Result<Option<Vec<u8>>>>,
+    },
+    Put {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        sender: oneshot::Sender<errors::Result<()>>,
+    },
+    Provide {
+        key: Vec<u8>,
+        path: PathBuf,
+        sender: oneshot::Sender<errors::Result<()>>,
+    },
+    Fetch {
+        key: Vec<u8>,
+        sender: oneshot::Sender<errors::Result<Vec<u8>>>,
+    },
+}
+
+/// Our custom network behaviour, combining Kademlia, mDNS and the file-exchange
+/// request/response protocol.
+///
+/// The `pending_*` maps track in-flight queries/requests by the `QueryId`/`RequestId`
+/// handed back when they were issued, so the matching `oneshot::Sender` can be completed
+/// when the corresponding event arrives in `inject_event`. `providing` holds the on-disk
+/// path for every key this node has advertised via `kademlia.start_providing`, so an
+/// inbound file-exchange request can be answered by reading straight off disk.
+#[derive(NetworkBehaviour)]
+#[behaviour(event_process = true)]
+pub struct MyBehaviour {
+    pub(crate) kademlia: Kademlia<KvStore>,
+    mdns: Mdns,
+    request_response: RequestResponse<FileExchangeCodec>,
+    #[behaviour(ignore)]
+    pending_get: HashMap<QueryId, oneshot::Sender<errors::Result<Option<Vec<u8>>>>>,
+    #[behaviour(ignore)]
+    pending_put: HashMap<QueryId, oneshot::Sender<errors::Result<()>>>,
+    #[behaviour(ignore)]
+    pending_provide: HashMap<QueryId, oneshot::Sender<errors::Result<()>>>,
+    #[behaviour(ignore)]
+    pending_get_providers: HashMap<QueryId, (Vec<u8>, oneshot::Sender<errors::Result<Vec<u8>>>)>,
+    #[behaviour(ignore)]
+    pending_fetch: HashMap<RequestId, oneshot::Sender<errors::Result<Vec<u8>>>>,
+    #[behaviour(ignore)]
+    providing: HashMap<Vec<u8>, PathBuf>,
+    #[behaviour(ignore)]
+    response_sender: mpsc::UnboundedSender<PendingResponse>,
+    #[behaviour(ignore)]
+    local_peer_id: PeerId,
+}
+
+impl MyBehaviour {
+    pub fn new(
+        kademlia: Kademlia<KvStore>,
+        mdns: Mdns,
+        request_response: RequestResponse<FileExchangeCodec>,
+        response_sender: mpsc::UnboundedSender<PendingResponse>,
+        local_peer_id: PeerId,
+    ) -> Self {
+        MyBehaviour {
+            kademlia,
+            mdns,
+            request_response,
+            pending_get: HashMap::new(),
+            pending_put: HashMap::new(),
+            pending_provide: HashMap::new(),
+            pending_get_providers: HashMap::new(),
+            pending_fetch: HashMap::new(),
+            providing: HashMap::new(),
+            response_sender,
+            local_peer_id,
+        }
+    }
+
+    /// Read a locally-provided file the same way the inbound `FileRequest` handler does,
+    /// for the case where `get_providers` resolves to this node itself: dialing ourselves
+    /// through `request_response` would fail (or hang) instead of just reading the file.
+    fn read_local_provided_file(&self, key: &[u8], sender: oneshot::Sender<errors::Result<Vec<u8>>>) {
+        let path = self.providing.get(key).cloned();
+        task::spawn(async move {
+            let result = match path {
+                Some(path) => async_std::fs::read(&path).await.map_err(|err| {
+                    ErrorKind::Msg(format!("Failed to read provided file {:?}: {:?}", path, err))
+                        .into()
+                }),
+                None => Err(ErrorKind::Msg("No providers found".into()).into()),
+            };
+            let _ = sender.send(result);
+        });
+    }
+}
+
+impl NetworkBehaviourEventProcess<MdnsEvent> for MyBehaviour {
+    // Called when `mdns` produces an event.
+    fn inject_event(&mut self, event: MdnsEvent) {
+        if let MdnsEvent::Discovered(list) = event {
+            for (peer_id, multiaddr) in list {
+                println!("New Peer '{}' at {} added to network", peer_id, multiaddr);
+                self.kademlia.add_address(&peer_id, multiaddr);
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<KademliaEvent> for MyBehaviour {
+    fn inject_event(&mut self, message: KademliaEvent) {
+        if let KademliaEvent::OutboundQueryCompleted { id, result, .. } = message {
+            match result {
+                QueryResult::GetRecord(result) => {
+                    if let Some(sender) = self.pending_get.remove(&id) {
+                        let response = match result {
+                            Ok(GetRecordOk { mut records, .. }) => {
+                                Ok(records.pop().map(|record| record.record.value))
+                            }
+                            // A missing key surfaces as an error from Kademlia, but it's
+                            // an expected outcome for `Client::get`, not a failure: report
+                            // it the same way as an empty `Ok` result, via `Ok(None)`.
+                            Err(GetRecordError::NotFound { .. }) => Ok(None),
+                            Err(err) => Err(ErrorKind::Msg(format!(
+                                "Failed to get record: {:?}",
+                                err
+                            ))
+                            .into()),
+                        };
+                        let _ = sender.send(response);
+                    }
+                }
+                QueryResult::PutRecord(result) => {
+                    if let Some(sender) = self.pending_put.remove(&id) {
+                        let response = match result {
+                            Ok(PutRecordOk { .. }) => Ok(()),
+                            Err(err) => Err(ErrorKind::Msg(format!(
+                                "Failed to put record: {:?}",
+                                err
+                            ))
+                            .into()),
+                        };
+                        let _ = sender.send(response);
+                    }
+                }
+                QueryResult::Bootstrap(result) => match result {
+                    Ok(ok) if ok.num_remaining == 0 => {
+                        println!("Bootstrap complete, routing table seeded");
+                    }
+                    Ok(_) => {}
+                    Err(err) => eprintln!("Bootstrap failed: {:?}", err),
+                },
+                QueryResult::StartProviding(result) => {
+                    if let Some(sender) = self.pending_provide.remove(&id) {
+                        let response = match result {
+                            Ok(_) => Ok(()),
+                            Err(err) => Err(ErrorKind::Msg(format!(
+                                "Failed to start providing: {:?}",
+                                err
+                            ))
+                            .into()),
+                        };
+                        let _ = sender.send(response);
+                    }
+                }
+                QueryResult::GetProviders(result) => {
+                    if let Some((key, sender)) = self.pending_get_providers.remove(&id) {
+                        let providers = match result {
+                            Ok(GetProvidersOk { providers, .. }) => providers,
+                            Err(err) => {
+                                let _ = sender.send(Err(ErrorKind::Msg(format!(
+                                    "Failed to get providers: {:?}",
+                                    err
+                                ))
+                                .into()));
+                                return;
+                            }
+                        };
+                        match providers.into_iter().next() {
+                            // `start_providing` registers us as a provider of our own
+                            // records, so `get_providers` can resolve to `local_peer_id`
+                            // right after a local `PROVIDE`. `request_response` has no
+                            // connection to dial to ourselves, so read the file directly
+                            // instead of round-tripping it through the network.
+                            Some(peer) if peer == self.local_peer_id => {
+                                self.read_local_provided_file(&key, sender);
+                            }
+                            Some(peer) => {
+                                // `RequestResponse` keeps its own address book and never
+                                // consults Kademlia's routing table, so unless we're
+                                // already connected to `peer` a bare `send_request` fails
+                                // with `OutboundFailure::DialFailure`. Seed it with every
+                                // address Kademlia knows for this peer first.
+                                for addr in self.kademlia.addresses_of_peer(&peer) {
+                                    self.request_response.add_address(&peer, addr);
+                                }
+                                let request_id = self
+                                    .request_response
+                                    .send_request(&peer, FileRequest(key));
+                                self.pending_fetch.insert(request_id, sender);
+                            }
+                            None => {
+                                let _ = sender
+                                    .send(Err(ErrorKind::Msg("No providers found".into()).into()));
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<FileRequest, FileResponse>> for MyBehaviour {
+    fn inject_event(&mut self, event: RequestResponseEvent<FileRequest, FileResponse>) {
+        match event {
+            RequestResponseEvent::Message {
+                message: RequestResponseMessage::Request { request, channel, .. },
+                ..
+            } => {
+                // Reading the provided file can be an unbounded, slow disk operation, so
+                // do it off the swarm task rather than blocking `inject_event` (and every
+                // other in-flight query) for however long it takes. The result comes back
+                // through `response_sender` and is handed to `request_response` by `run`,
+                // which is the only place that owns the swarm.
+                let path = self.providing.get(&request.0).cloned();
+                let mut response_sender = self.response_sender.clone();
+                task::spawn(async move {
+                    let bytes = match path {
+                        Some(path) => async_std::fs::read(&path).await.unwrap_or_else(|err| {
+                            eprintln!("Failed to read provided file {:?}: {:?}", path, err);
+                            Vec::new()
+                        }),
+                        None => Vec::new(),
+                    };
+                    let _ = response_sender.send((channel, FileResponse(bytes))).await;
+                });
+            }
+            RequestResponseEvent::Message {
+                message: RequestResponseMessage::Response { request_id, response },
+                ..
+            } => {
+                if let Some(sender) = self.pending_fetch.remove(&request_id) {
+                    let result = if response.0.is_empty() {
+                        Err(ErrorKind::Msg("Provider did not have the requested value".into()).into())
+                    } else {
+                        Ok(response.0)
+                    };
+                    let _ = sender.send(result);
+                }
+            }
+            RequestResponseEvent::OutboundFailure { request_id, error, .. } => {
+                if let Some(sender) = self.pending_fetch.remove(&request_id) {
+                    let _ = sender.send(Err(
+                        ErrorKind::Msg(format!("Transfer failed: {:?}", error)).into()
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Build a [`Client`]/event-loop pair: `run` the returned future (e.g. via
+/// `async_std::task::spawn`) to drive `swarm` and service commands sent through the
+/// `Client`. `response_receiver` is the matching half of the [`response_channel`] that was
+/// handed to `swarm`'s [`MyBehaviour`] when it was constructed.
+pub fn new(
+    swarm: Swarm<MyBehaviour>,
+    response_receiver: mpsc::UnboundedReceiver<PendingResponse>,
+) -> (Client, impl Future<Output = ()>) {
+    let (sender, receiver) = mpsc::channel(32);
+    (Client { sender }, run(swarm, receiver, response_receiver))
+}
+
+/// Drive `swarm`, servicing [`Command`]s from `command_receiver` until the channel closes.
+///
+/// This owns the swarm for as long as the KVS node is running: it is the only place that
+/// calls `kademlia.get_record`/`put_record`, recording the returned `QueryId` in the
+/// behaviour's `pending_get`/`pending_put` maps so `inject_event` can later complete the
+/// matching `oneshot::Sender`. `response_receiver` carries file-read results back from the
+/// spawned tasks in [`MyBehaviour`]'s `RequestResponseEvent` handler, since only `run` may
+/// touch `request_response.send_response`.
+async fn run(
+    mut swarm: Swarm<MyBehaviour>,
+    mut command_receiver: mpsc::Receiver<Command>,
+    mut response_receiver: mpsc::UnboundedReceiver<PendingResponse>,
+) {
+    loop {
+        futures::select! {
+            event = swarm.select_next_some() => {
+                if let SwarmEvent::NewListenAddr { address, .. } = event {
+                    println!("Listening on {:?}", address);
+                }
+            },
+            command = command_receiver.next() => match command {
+                Some(command) => handle_command(&mut swarm, command),
+                None => return,
+            },
+            response = response_receiver.next() => {
+                if let Some((channel, response)) = response {
+                    let _ = swarm.behaviour_mut().request_response.send_response(channel, response);
+                }
+            },
+        }
+    }
+}
+
+fn handle_command(swarm: &mut Swarm<MyBehaviour>, command: Command) {
+    match command {
+        Command::Get { key, sender } => {
+            let query_id = swarm.behaviour_mut().kademlia.get_record(Key::new(&key), Quorum::One);
+            swarm.behaviour_mut().pending_get.insert(query_id, sender);
+        }
+        Command::Put { key, value, sender } => {
+            let record = Record {
+                key: Key::new(&key),
+                value,
+                publisher: None,
+                expires: None,
+            };
+            match swarm.behaviour_mut().kademlia.put_record(record, Quorum::One) {
+                Ok(query_id) => {
+                    swarm.behaviour_mut().pending_put.insert(query_id, sender);
+                }
+                Err(err) => {
+                    let _ = sender.send(Err(ErrorKind::P2P(err).into()));
+                }
+            }
+        }
+        Command::Provide { key, path, sender } => {
+            match swarm.behaviour_mut().kademlia.start_providing(Key::new(&key)) {
+                Ok(query_id) => {
+                    swarm.behaviour_mut().providing.insert(key, path);
+                    swarm.behaviour_mut().pending_provide.insert(query_id, sender);
+                }
+                Err(err) => {
+                    let _ = sender
+                        .send(Err(ErrorKind::Msg(format!("Failed to start providing: {:?}", err)).into()));
+                }
+            }
+        }
+        Command::Fetch { key, sender } => {
+            let query_id = swarm.behaviour_mut().kademlia.get_providers(Key::new(&key));
+            swarm
+                .behaviour_mut()
+                .pending_get_providers
+                .insert(query_id, (key, sender));
+        }
+    }
+}
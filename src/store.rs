@@ -0,0 +1,491 @@
+//! `RecordStore` implementations for the Kademlia behaviour.
+//!
+//! [`MemoryStore`] loses every PUT when the process exits, which is fine for quick testing
+//! but not for a node that is meant to stay part of the DHT across restarts. [`DiskStore`]
+//! persists records and provider records to a [`sled`] database on disk and reloads them
+//! on startup, dropping anything that has already expired.
+
+use std::borrow::Cow;
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use libp2p::kad::record::store::{Error, MemoryStore, Result};
+use libp2p::kad::record::{Key, ProviderRecord, Record};
+use libp2p::kad::store::RecordStore;
+use libp2p::{Multiaddr, PeerId};
+
+/// Either an in-memory or an on-disk record store, selected by [`crate::create_store`].
+///
+/// Kademlia is generic over its `RecordStore`, so rather than pick a type at compile time
+/// we dispatch each trait method to whichever variant was configured for this run.
+pub enum KvStore {
+    Memory(MemoryStore),
+    Disk(DiskStore),
+}
+
+impl<'a> RecordStore<'a> for KvStore {
+    type RecordsIter = Box<dyn Iterator<Item = Cow<'a, Record>> + 'a>;
+    type ProvidedIter = Box<dyn Iterator<Item = Cow<'a, ProviderRecord>> + 'a>;
+
+    fn get(&'a self, k: &Key) -> Option<Cow<'_, Record>> {
+        match self {
+            KvStore::Memory(store) => store.get(k),
+            KvStore::Disk(store) => store.get(k),
+        }
+    }
+
+    fn put(&mut self, r: Record) -> Result<()> {
+        match self {
+            KvStore::Memory(store) => store.put(r),
+            KvStore::Disk(store) => store.put(r),
+        }
+    }
+
+    fn remove(&mut self, k: &Key) {
+        match self {
+            KvStore::Memory(store) => store.remove(k),
+            KvStore::Disk(store) => store.remove(k),
+        }
+    }
+
+    fn records(&'a self) -> Self::RecordsIter {
+        match self {
+            KvStore::Memory(store) => Box::new(store.records()),
+            KvStore::Disk(store) => Box::new(store.records()),
+        }
+    }
+
+    fn add_provider(&mut self, record: ProviderRecord) -> Result<()> {
+        match self {
+            KvStore::Memory(store) => store.add_provider(record),
+            KvStore::Disk(store) => store.add_provider(record),
+        }
+    }
+
+    fn providers(&self, key: &Key) -> Vec<ProviderRecord> {
+        match self {
+            KvStore::Memory(store) => store.providers(key),
+            KvStore::Disk(store) => store.providers(key),
+        }
+    }
+
+    fn provided(&'a self) -> Self::ProvidedIter {
+        match self {
+            KvStore::Memory(store) => Box::new(store.provided()),
+            KvStore::Disk(store) => Box::new(store.provided()),
+        }
+    }
+
+    fn remove_provider(&mut self, k: &Key, p: &PeerId) {
+        match self {
+            KvStore::Memory(store) => store.remove_provider(k, p),
+            KvStore::Disk(store) => store.remove_provider(k, p),
+        }
+    }
+}
+
+/// A `RecordStore` backed by a `sled` database, so records and provider records survive
+/// restarts. Records that have already expired are dropped when the database is opened.
+pub struct DiskStore {
+    records: sled::Tree,
+    providers: sled::Tree,
+}
+
+const RECORDS_TREE: &str = "records";
+const PROVIDERS_TREE: &str = "providers";
+
+impl DiskStore {
+    /// Open (or create) the database at `path`, pruning any record whose `expires`
+    /// timestamp is already in the past.
+    pub fn open(path: impl AsRef<Path>) -> errors::Result<Self> {
+        let db = sled::Config::new().path(path).open()?;
+        let records = db.open_tree(RECORDS_TREE)?;
+        let providers = db.open_tree(PROVIDERS_TREE)?;
+
+        let expired_keys: Vec<_> = records
+            .iter()
+            .values()
+            .filter_map(|value| value.ok())
+            .filter_map(|bytes| StoredRecord::decode(&bytes))
+            .filter(|stored| stored.is_expired())
+            .map(|stored| stored.record.key.to_vec())
+            .collect();
+        for key in expired_keys {
+            records.remove(key)?;
+        }
+
+        let expired_provider_rows: Vec<_> = providers
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|(_, value)| {
+                StoredProvider::decode(value).map(|stored| stored.is_expired()).unwrap_or(false)
+            })
+            .map(|(row_key, _)| row_key)
+            .collect();
+        for row_key in expired_provider_rows {
+            providers.remove(row_key)?;
+        }
+
+        Ok(DiskStore { records, providers })
+    }
+
+    fn records_tree(&self) -> &sled::Tree {
+        &self.records
+    }
+}
+
+impl<'a> RecordStore<'a> for DiskStore {
+    type RecordsIter = Box<dyn Iterator<Item = Cow<'a, Record>> + 'a>;
+    type ProvidedIter = Box<dyn Iterator<Item = Cow<'a, ProviderRecord>> + 'a>;
+
+    fn get(&'a self, k: &Key) -> Option<Cow<'_, Record>> {
+        let bytes = self.records_tree().get(k.as_ref()).ok()??;
+        let stored = StoredRecord::decode(&bytes)?;
+        if stored.is_expired() {
+            None
+        } else {
+            Some(Cow::Owned(stored.record))
+        }
+    }
+
+    fn put(&mut self, r: Record) -> Result<()> {
+        let stored = StoredRecord::new(r);
+        self.records_tree()
+            .insert(stored.record.key.as_ref(), stored.encode())
+            .map_err(|_| Error::ValueTooLarge)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, k: &Key) {
+        let _ = self.records_tree().remove(k.as_ref());
+    }
+
+    fn records(&'a self) -> Self::RecordsIter {
+        Box::new(
+            self.records_tree()
+                .iter()
+                .values()
+                .filter_map(|value| value.ok())
+                .filter_map(|bytes| StoredRecord::decode(&bytes))
+                .filter(|stored| !stored.is_expired())
+                .map(|stored| Cow::Owned(stored.record)),
+        )
+    }
+
+    fn add_provider(&mut self, record: ProviderRecord) -> Result<()> {
+        let stored = StoredProvider::new(record);
+        let row_key = provider_row_key(&stored.record.key, &stored.record.provider);
+        self.providers
+            .insert(row_key, stored.encode())
+            .map_err(|_| Error::ValueTooLarge)?;
+        Ok(())
+    }
+
+    fn providers(&self, key: &Key) -> Vec<ProviderRecord> {
+        self.providers
+            .scan_prefix(provider_key_prefix(key))
+            .values()
+            .filter_map(|value| value.ok())
+            .filter_map(|bytes| StoredProvider::decode(&bytes))
+            .filter(|stored| !stored.is_expired())
+            .map(|stored| stored.record)
+            .collect()
+    }
+
+    fn provided(&'a self) -> Self::ProvidedIter {
+        Box::new(
+            self.providers
+                .iter()
+                .values()
+                .filter_map(|value| value.ok())
+                .filter_map(|bytes| StoredProvider::decode(&bytes))
+                .filter(|stored| !stored.is_expired())
+                .map(|stored| Cow::Owned(stored.record)),
+        )
+    }
+
+    fn remove_provider(&mut self, k: &Key, p: &PeerId) {
+        let _ = self.providers.remove(provider_row_key(k, p));
+    }
+}
+
+/// Kademlia keys are raw, variable-length bytes, so a plain `key ++ provider_id` row key
+/// would let `scan_prefix(key)` also match rows stored under any key that has `key` as a
+/// byte-prefix (e.g. looking up `"a"` would also return providers for `"ab"`). Prefixing
+/// the row key with the encoded key's fixed-width length disambiguates: two rows only
+/// share this prefix if their key is the same length *and* the same bytes.
+fn provider_key_prefix(key: &Key) -> Vec<u8> {
+    let key_bytes = key.as_ref();
+    let mut prefix = (key_bytes.len() as u32).to_be_bytes().to_vec();
+    prefix.extend_from_slice(key_bytes);
+    prefix
+}
+
+/// The full row key for a provider record: [`provider_key_prefix`] followed by the
+/// provider's peer id, so multiple providers of the same key get distinct rows.
+fn provider_row_key(key: &Key, provider: &PeerId) -> Vec<u8> {
+    let mut row_key = provider_key_prefix(key);
+    row_key.extend_from_slice(provider.as_ref());
+    row_key
+}
+
+/// A `Record` plus its `expires` timestamp, stored on disk as seconds-since-epoch so it
+/// can be compared against the wall clock after a restart (a libp2p `Instant` can't be).
+struct StoredRecord {
+    record: Record,
+    expires_unix: Option<u64>,
+}
+
+impl StoredRecord {
+    fn new(record: Record) -> Self {
+        let expires_unix = record.expires.map(instant_to_unix);
+        StoredRecord { record, expires_unix }
+    }
+
+    fn is_expired(&self) -> bool {
+        match self.expires_unix {
+            Some(expires_unix) => unix_now() >= expires_unix,
+            None => false,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        bincode_encode(&(
+            self.record.key.to_vec(),
+            self.record.value.clone(),
+            self.record.publisher.map(|p| p.to_bytes()),
+            self.expires_unix,
+        ))
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (key, value, publisher, expires_unix): (
+            Vec<u8>,
+            Vec<u8>,
+            Option<Vec<u8>>,
+            Option<u64>,
+        ) = bincode_decode(bytes)?;
+        let record = Record {
+            key: Key::from(key),
+            value,
+            publisher: publisher.and_then(|bytes| PeerId::from_bytes(&bytes).ok()),
+            expires: expires_unix.map(unix_to_instant),
+        };
+        Some(StoredRecord { record, expires_unix })
+    }
+}
+
+/// A `ProviderRecord` plus its `expires` timestamp, stored the same way as [`StoredRecord`].
+struct StoredProvider {
+    record: ProviderRecord,
+    expires_unix: Option<u64>,
+}
+
+impl StoredProvider {
+    fn new(record: ProviderRecord) -> Self {
+        let expires_unix = record.expires.map(instant_to_unix);
+        StoredProvider { record, expires_unix }
+    }
+
+    fn is_expired(&self) -> bool {
+        match self.expires_unix {
+            Some(expires_unix) => unix_now() >= expires_unix,
+            None => false,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let addresses: Vec<String> = self.record.addresses.iter().map(Multiaddr::to_string).collect();
+        bincode_encode(&(
+            self.record.key.to_vec(),
+            self.record.provider.to_bytes(),
+            self.expires_unix,
+            addresses,
+        ))
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (key, provider, expires_unix, addresses): (
+            Vec<u8>,
+            Vec<u8>,
+            Option<u64>,
+            Vec<String>,
+        ) = bincode_decode(bytes)?;
+        let addresses = addresses
+            .iter()
+            .filter_map(|addr| addr.parse().ok())
+            .collect();
+        let record = ProviderRecord {
+            key: Key::from(key),
+            provider: PeerId::from_bytes(&provider).ok()?,
+            expires: expires_unix.map(unix_to_instant),
+            addresses,
+        };
+        Some(StoredProvider { record, expires_unix })
+    }
+}
+
+fn bincode_encode<T: serde::Serialize>(value: &T) -> Vec<u8> {
+    bincode::serialize(value).expect("in-memory struct always serializes")
+}
+
+fn bincode_decode<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> Option<T> {
+    bincode::deserialize(bytes).ok()
+}
+
+/// `Instant` has no stable epoch, so we can only persist expiry as an offset from "now"
+/// at write time and re-derive an `Instant` the same way on read.
+fn instant_to_unix(instant: Instant) -> u64 {
+    let now = Instant::now();
+    let offset = instant.saturating_duration_since(now);
+    unix_now() + offset.as_secs()
+}
+
+fn unix_to_instant(expires_unix: u64) -> Instant {
+    let remaining = expires_unix.saturating_sub(unix_now());
+    Instant::now() + std::time::Duration::from_secs(remaining)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+use crate::errors;
+
+impl From<sled::Error> for errors::Error {
+    fn from(err: sled::Error) -> Self {
+        errors::ErrorKind::Msg(format!("sled error: {}", err)).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity;
+
+    fn temp_dir() -> std::path::PathBuf {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+        std::env::temp_dir().join(format!("libp2pkvs-store-test-{}", nanos))
+    }
+
+    fn peer_id() -> PeerId {
+        PeerId::from(identity::Keypair::generate_ed25519().public())
+    }
+
+    #[test]
+    fn stored_record_round_trips_through_encode_decode() {
+        let record = Record::new(Key::new(&"k"), Vec::from("v"));
+        let stored = StoredRecord::new(record.clone());
+
+        let decoded = StoredRecord::decode(&stored.encode()).expect("decodes");
+
+        assert_eq!(decoded.record.key, record.key);
+        assert_eq!(decoded.record.value, record.value);
+        assert!(!decoded.is_expired());
+    }
+
+    #[test]
+    fn stored_record_with_past_expiry_is_expired() {
+        let mut record = Record::new(Key::new(&"k"), Vec::from("v"));
+        record.expires = Some(Instant::now() - std::time::Duration::from_secs(60));
+
+        assert!(StoredRecord::new(record).is_expired());
+    }
+
+    #[test]
+    fn stored_provider_round_trips_through_encode_decode() {
+        let provider = peer_id();
+        let record = ProviderRecord {
+            key: Key::new(&"k"),
+            provider,
+            expires: None,
+            addresses: Vec::new(),
+        };
+        let stored = StoredProvider::new(record.clone());
+
+        let decoded = StoredProvider::decode(&stored.encode()).expect("decodes");
+
+        assert_eq!(decoded.record.key, record.key);
+        assert_eq!(decoded.record.provider, record.provider);
+        assert!(!decoded.is_expired());
+    }
+
+    #[test]
+    fn stored_provider_round_trips_its_addresses() {
+        let record = ProviderRecord {
+            key: Key::new(&"k"),
+            provider: peer_id(),
+            expires: None,
+            addresses: vec![
+                "/ip4/127.0.0.1/tcp/4001".parse().unwrap(),
+                "/ip4/10.0.0.1/tcp/4002".parse().unwrap(),
+            ],
+        };
+        let stored = StoredProvider::new(record.clone());
+
+        let decoded = StoredProvider::decode(&stored.encode()).expect("decodes");
+
+        assert_eq!(decoded.record.addresses, record.addresses);
+    }
+
+    #[test]
+    fn providers_does_not_match_a_key_that_is_only_a_byte_prefix() {
+        let path = temp_dir();
+        let mut store = DiskStore::open(&path).expect("opens");
+        let record = ProviderRecord {
+            key: Key::new(&"ab"),
+            provider: peer_id(),
+            expires: None,
+            addresses: Vec::new(),
+        };
+        store.add_provider(record).expect("adds provider");
+
+        assert!(store.providers(&Key::new(&"a")).is_empty());
+        assert_eq!(store.providers(&Key::new(&"ab")).len(), 1);
+
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn records_and_providers_survive_a_reopen_and_expired_ones_are_pruned() {
+        let path = temp_dir();
+
+        {
+            let mut store = DiskStore::open(&path).expect("opens");
+            store.put(Record::new(Key::new(&"live"), Vec::from("v"))).expect("puts live record");
+            let mut expired_record = Record::new(Key::new(&"dead"), Vec::from("v"));
+            expired_record.expires = Some(Instant::now() - std::time::Duration::from_secs(60));
+            store.put(expired_record).expect("puts expired record");
+
+            store
+                .add_provider(ProviderRecord {
+                    key: Key::new(&"live"),
+                    provider: peer_id(),
+                    expires: None,
+                    addresses: Vec::new(),
+                })
+                .expect("adds live provider");
+            store
+                .add_provider(ProviderRecord {
+                    key: Key::new(&"dead"),
+                    provider: peer_id(),
+                    expires: Some(Instant::now() - std::time::Duration::from_secs(60)),
+                    addresses: Vec::new(),
+                })
+                .expect("adds expired provider");
+        }
+
+        // Drop the store and re-open the same path, the way a restart would.
+        let store = DiskStore::open(&path).expect("reopens");
+
+        assert_eq!(store.get(&Key::new(&"live")).map(|r| r.value.clone()), Some(Vec::from("v")));
+        assert_eq!(store.get(&Key::new(&"dead")), None);
+        assert_eq!(store.providers(&Key::new(&"live")).len(), 1);
+        assert!(store.providers(&Key::new(&"dead")).is_empty());
+
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
@@ -0,0 +1,93 @@
+//! The `PROVIDE`/`FETCH` transfer path for values too large for a single Kademlia record.
+//!
+//! Kademlia records are size-capped and replicated to every close peer, which is wrong for
+//! large blobs. Instead a node that has a large value calls `kademlia.start_providing` to
+//! advertise only the key in the DHT and keeps the bytes on disk. A node that wants the
+//! value resolves providers for the key via `kademlia.get_providers`, then pulls the bytes
+//! directly from one of them over this `libp2p-request-response` protocol rather than
+//! through a DHT record.
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed};
+use libp2p::request_response::{ProtocolName, RequestResponseCodec};
+use std::io;
+
+/// The largest transfer this protocol will read before giving up, guarding against a
+/// misbehaving peer claiming an unbounded length prefix.
+const MAX_TRANSFER_SIZE: usize = 100 * 1024 * 1024;
+
+/// The wire name for this request/response protocol, distinct from Kademlia's own.
+#[derive(Debug, Clone, Default)]
+pub struct FileExchangeProtocol;
+
+impl ProtocolName for FileExchangeProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        "/libp2pkvs/file-exchange/1.0.0".as_bytes()
+    }
+}
+
+/// A request for the bytes a provider has advertised under this key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileRequest(pub Vec<u8>);
+
+/// The requested bytes, or empty if the provider no longer has them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileResponse(pub Vec<u8>);
+
+/// Reads/writes a [`FileRequest`]/[`FileResponse`] as a single length-prefixed blob.
+#[derive(Clone, Default)]
+pub struct FileExchangeCodec;
+
+#[async_trait]
+impl RequestResponseCodec for FileExchangeCodec {
+    type Protocol = FileExchangeProtocol;
+    type Request = FileRequest;
+    type Response = FileResponse;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &FileExchangeProtocol,
+        io: &mut T,
+    ) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        Ok(FileRequest(read_length_prefixed(io, MAX_TRANSFER_SIZE).await?))
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &FileExchangeProtocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        Ok(FileResponse(read_length_prefixed(io, MAX_TRANSFER_SIZE).await?))
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &FileExchangeProtocol,
+        io: &mut T,
+        FileRequest(data): FileRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, data).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &FileExchangeProtocol,
+        io: &mut T,
+        FileResponse(data): FileResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, data).await
+    }
+}